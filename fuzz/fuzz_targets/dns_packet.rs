@@ -0,0 +1,39 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use duwop::dns::protocol::{BytePacketBuffer, DnsPacket, DnsRecord};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = BytePacketBuffer::new();
+    let len = data.len().min(buffer.buf.len());
+    buffer.buf[..len].copy_from_slice(&data[..len]);
+    let mut packet = match DnsPacket::from_buffer(&mut buffer) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+
+    // Unknown records are dropped when writing, so they can't round trip.
+    let records = packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.resources);
+    let has_unknown = records
+        .clone()
+        .any(|rec| matches!(rec, DnsRecord::UNKNOWN { .. }));
+
+    let mut written = BytePacketBuffer::new();
+    if packet.write(&mut written).is_err() || has_unknown {
+        return;
+    }
+    written.pos = 0;
+    let parsed = DnsPacket::from_buffer(&mut written).expect("written packet must parse");
+    assert_eq!(
+        format!("{:?}", parsed.header),
+        format!("{:?}", packet.header)
+    );
+    assert_eq!(parsed.questions, packet.questions);
+    assert_eq!(parsed.answers, packet.answers);
+    assert_eq!(parsed.authorities, packet.authorities);
+    assert_eq!(parsed.resources, packet.resources);
+});
@@ -1,59 +1,286 @@
-mod protocol;
+pub mod protocol;
+mod rate_limit;
+mod stats;
 
+use crate::ports::{self, Protocol};
 use protocol::*;
+use rate_limit::RateLimiter;
+pub use stats::DnsStats;
 
-use std::io::{self, Result};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::io::Result;
+use std::net::{self, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use futures::future::Future;
-use futures::try_ready;
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use tokio::net::UdpSocket;
-use tokio::prelude::*;
+use tokio::sync::mpsc;
+
+// Generous enough for browser bursts, low enough to stop a runaway query
+// loop from keeping us busy.
+const DEFAULT_QUERIES_PER_SECOND: u32 = 500;
+
+// Responses waiting to be sent. When full we stop reading requests until
+// some are sent.
+const MAX_PENDING_RESPONSES: usize = 256;
+
+// We serve the whole zone, so no caching by default lets changes take effect
+// immediately.
+const DEFAULT_TTL: u32 = 0;
+const ZONE: &str = "test";
+const ZONE_SUFFIX: &str = ".test";
+const NAME_SERVER: &str = "ns.test";
+const HOSTMASTER: &str = "hostmaster.test";
+
+// We only support EDNS version 0 and never send more than a plain dns
+// packet.
+const EDNS_VERSION: u8 = 0;
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 512;
+// Extended rcode (upper 8 bits of the full 12 bit rcode) for BADVERS (16)
+const EDNS_BADVERS: u32 = 1;
 
 pub struct DNSServer {
-    socket: UdpSocket,
+    socket: net::UdpSocket,
+    responder: Responder,
 }
 
 impl DNSServer {
     pub fn new(port: u16) -> Result<DNSServer> {
         let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
-        info!("listening for dns requests on {}", &addr);
-        let socket = UdpSocket::bind(&addr)?;
-        Ok(DNSServer { socket })
+        let socket =
+            net::UdpSocket::bind(addr).map_err(|e| ports::bind_error(e, Protocol::Udp, port))?;
+        DNSServer::from_std(socket)
     }
-}
 
-impl Future for DNSServer {
-    type Item = ();
-    type Error = io::Error;
+    /// Creates a server from an already bound socket (e.g. one handed over by
+    /// launchd socket activation).
+    pub fn from_std(socket: net::UdpSocket) -> Result<DNSServer> {
+        socket.set_nonblocking(true)?;
+        info!("listening for dns requests on {}", socket.local_addr()?);
+        Ok(DNSServer {
+            socket,
+            responder: Responder {
+                stats: Arc::new(Mutex::new(DnsStats::default())),
+                rate_limiter: RateLimiter::new(DEFAULT_QUERIES_PER_SECOND),
+                ttl: DEFAULT_TTL,
+                log_unexpected_sources: false,
+            },
+        })
+    }
+
+    /// Sets the ttl of the records we answer with (and the negative caching
+    /// ttl advertised in our SOA record).
+    pub fn ttl(mut self, ttl: u32) -> DNSServer {
+        self.responder.ttl = ttl;
+        self
+    }
+
+    /// Logs the first query from every non-localhost source. Helps finding
+    /// which program keeps sending us queries.
+    pub fn log_unexpected_sources(mut self, enabled: bool) -> DNSServer {
+        self.responder.log_unexpected_sources = enabled;
+        self
+    }
+
+    /// Sets the number of queries per second a single source may send before
+    /// being answered with REFUSED.
+    pub fn rate_limit(mut self, queries_per_second: u32) -> DNSServer {
+        self.responder.rate_limiter = RateLimiter::new(queries_per_second);
+        self
+    }
+
+    /// Creates a server from the launchd socket entry `name`. Lets the agent
+    /// answer on port 53 without running as root.
+    #[cfg(target_os = "macos")]
+    pub fn from_launchd(name: &str) -> Result<DNSServer> {
+        use std::io::{Error, ErrorKind};
+        use std::os::unix::io::FromRawFd;
+
+        let mut sockets: Vec<net::UdpSocket> = crate::launchd::activate_socket(name)?
+            .into_iter()
+            .map(|fd| unsafe { net::UdpSocket::from_raw_fd(fd) })
+            .collect();
+        // We serve a single socket. Any other sockets are closed when dropped.
+        match sockets.len() {
+            1 => DNSServer::from_std(sockets.remove(0)),
+            0 => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no sockets activated for launchd entry {}", name),
+            )),
+            n => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "launchd entry {} activated {} sockets, expected one (set its SockFamily to IPv4)",
+                    name, n
+                ),
+            )),
+        }
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// A handle to the server's query counters. Keep a clone before running
+    /// the server to inspect them while it runs.
+    pub fn stats(&self) -> Arc<Mutex<DnsStats>> {
+        Arc::clone(&self.responder.stats)
+    }
+
+    /// Serves requests until receiving from the socket fails. Must be called
+    /// from within a tokio runtime.
+    pub async fn run(self) -> Result<()> {
+        let DNSServer {
+            socket,
+            mut responder,
+        } = self;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+
+        // Responses are sent from their own task so a slow send doesn't keep
+        // us from reading further requests.
+        let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(MAX_PENDING_RESPONSES);
+        let sender = Arc::clone(&socket);
+        tokio::spawn(async move {
+            while let Some((data, peer)) = rx.recv().await {
+                match sender.send_to(&data, peer).await {
+                    Ok(amt) => debug!("sent {} response bytes to {}", amt, peer),
+                    Err(e) => warn!("failed to send dns response to {}: {}", peer, e),
+                }
+            }
+        });
 
-    fn poll(&mut self) -> Poll<(), io::Error> {
         loop {
             let mut req_buffer = BytePacketBuffer::new();
-            let to_send = Some(try_ready!(self.socket.poll_recv_from(&mut req_buffer.buf)));
-            let request = DnsPacket::from_buffer(&mut req_buffer)?;
-            debug!("received request {:#?}", &request.questions);
-            let mut response = lookup(&request)?;
-            if let Some((size, peer)) = to_send {
-                let mut res_buffer = BytePacketBuffer::new();
-                response.write(&mut res_buffer)?;
-                let len = res_buffer.pos();
-                let data = res_buffer.get_range(0, len)?;
-                let amt = try_ready!(self.socket.poll_send_to(data, &peer));
-                debug!("Sent {}/{} response bytes to {}", amt, size, peer);
+            let (size, peer) = socket.recv_from(&mut req_buffer.buf).await?;
+            debug!("received {} bytes from {}", size, peer);
+            if let Some(response) = responder.handle(&mut req_buffer, peer) {
+                // The sender task only ends once we drop tx.
+                let _ = tx.send((response, peer)).await;
             }
         }
     }
 }
 
-fn lookup(request: &DnsPacket) -> Result<DnsPacket> {
-    let id = &request.header.id;
-    trace!("received query (id: {}): {:?}", &id, &request);
+/// Answers requests and keeps track of who sent them.
+struct Responder {
+    stats: Arc<Mutex<DnsStats>>,
+    rate_limiter: RateLimiter,
+    ttl: u32,
+    log_unexpected_sources: bool,
+}
+
+impl Responder {
+    /// Parses and answers a single request. Returns the serialized response
+    /// or None if there is nothing to send back.
+    fn handle(&mut self, req_buffer: &mut BytePacketBuffer, peer: SocketAddr) -> Option<Vec<u8>> {
+        // Checked before parsing so malformed packets count too.
+        let excess = self.rate_limiter.check(peer.ip(), Instant::now());
+        if excess == 1 {
+            warn!("rate limiting dns queries from {}", peer.ip());
+        }
+        let request = match DnsPacket::from_buffer(req_buffer) {
+            Ok(request) => request,
+            Err(e) => {
+                if excess == 0 {
+                    warn!("failed to parse dns request from {}: {}", peer, e);
+                }
+                self.record(&peer, |stats| stats.record_malformed(peer.ip()));
+                return None;
+            }
+        };
+        debug!("received request {:#?}", &request.questions);
+        match self.answer(&request, peer, excess > 0) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!("failed to answer dns request from {}: {}", peer, e);
+                None
+            }
+        }
+    }
+
+    fn answer(&mut self, request: &DnsPacket, peer: SocketAddr, limited: bool) -> Result<Vec<u8>> {
+        let mut response = if limited {
+            refuse(request)
+        } else {
+            lookup(request, self.ttl)?
+        };
+        self.record(&peer, |stats| {
+            stats.record_query(peer.ip(), request, &response)
+        });
+        write_response(&mut response)
+    }
+
+    fn record(&self, peer: &SocketAddr, record: impl FnOnce(&mut DnsStats) -> bool) {
+        let first_seen = record(&mut self.stats.lock().unwrap());
+        if self.log_unexpected_sources && first_seen && !peer.ip().is_loopback() {
+            warn!("received dns query from unexpected source: {}", peer.ip());
+        }
+    }
+}
+
+// Responses must fit in a single 512 byte packet. When they don't, only the
+// question (and OPT record) is sent back with the truncated flag set.
+fn write_response(response: &mut DnsPacket) -> Result<Vec<u8>> {
+    let mut res_buffer = BytePacketBuffer::new();
+    if let Err(err) = response.write(&mut res_buffer) {
+        if ProtocolError::from_io(&err) != Some(ProtocolError::EndOfBuffer) {
+            return Err(err);
+        }
+        debug!("response doesn't fit in a packet, truncating");
+        response.header.truncated_message = true;
+        response.answers.clear();
+        response.authorities.clear();
+        response
+            .resources
+            .retain(|rec| matches!(rec, DnsRecord::OPT { .. }));
+        res_buffer = BytePacketBuffer::new();
+        response.write(&mut res_buffer)?;
+    }
+    Ok(res_buffer.buf[..res_buffer.pos()].to_vec())
+}
+
+fn new_response(request: &DnsPacket) -> DnsPacket {
     let mut response = DnsPacket::new();
     response.header.response = true;
-    response.header.id = *id;
+    response.header.id = request.header.id;
     response.header.recursion_desired = request.header.recursion_desired;
+    if request.edns_version().is_some() {
+        response.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_UDP_PAYLOAD_SIZE,
+            flags: 0,
+        });
+    }
+    response
+}
+
+fn refuse(request: &DnsPacket) -> DnsPacket {
+    let mut response = new_response(request);
+    response
+        .questions
+        .extend(request.questions.first().cloned());
+    response.header.rescode = ResultCode::REFUSED;
+    response
+}
+
+fn soa_record(ttl: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: ZONE.to_string(),
+        m_name: NAME_SERVER.to_string(),
+        r_name: HOSTMASTER.to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: ttl,
+        ttl,
+    }
+}
+
+fn lookup(request: &DnsPacket, ttl: u32) -> Result<DnsPacket> {
+    let id = &request.header.id;
+    trace!("received query (id: {}): {:?}", &id, &request);
+    let mut response = new_response(request);
 
     if request.questions.is_empty() {
         response.header.rescode = ResultCode::NOTIMP;
@@ -63,6 +290,17 @@ fn lookup(request: &DnsPacket) -> Result<DnsPacket> {
     let query = &request.questions[0];
     response.questions.push(query.clone());
 
+    if let Some(version) = request.edns_version() {
+        if version != EDNS_VERSION {
+            warn!("unsupported edns version (id: {}): {}", &id, version);
+            response.resources = vec![DnsRecord::OPT {
+                packet_len: EDNS_UDP_PAYLOAD_SIZE,
+                flags: EDNS_BADVERS << 24,
+            }];
+            return Ok(response);
+        }
+    }
+
     if request.header.response {
         warn!("received response as question (id: {})", &id);
         response.header.rescode = ResultCode::NOTIMP;
@@ -75,7 +313,7 @@ fn lookup(request: &DnsPacket) -> Result<DnsPacket> {
         return Ok(response);
     }
 
-    if !query.name.ends_with(".test") {
+    if query.name != ZONE && !query.name.ends_with(ZONE_SUFFIX) {
         warn!("unsupported domain (id: {}): {}", &id, &query.name);
         response.header.rescode = ResultCode::SERVFAIL;
         return Ok(response);
@@ -86,34 +324,59 @@ fn lookup(request: &DnsPacket) -> Result<DnsPacket> {
             let record = DnsRecord::A {
                 addr: Ipv4Addr::LOCALHOST,
                 domain: query.name.to_string(),
-                ttl: 0,
+                ttl,
             };
             response.answers.push(record);
         }
+        QueryType::SOA if query.name == ZONE => {
+            response.answers.push(soa_record(ttl));
+        }
+        QueryType::NS if query.name == ZONE => {
+            response.answers.push(DnsRecord::NS {
+                domain: ZONE.to_string(),
+                host: NAME_SERVER.to_string(),
+                ttl,
+            });
+            response.resources.push(DnsRecord::A {
+                domain: NAME_SERVER.to_string(),
+                addr: Ipv4Addr::LOCALHOST,
+                ttl,
+            });
+        }
         QueryType::AAAA | QueryType::CNAME | QueryType::MX | QueryType::NS | QueryType::SOA => {
             debug!("received request for undefined query type: {:?}", &query);
             response.header.rescode = ResultCode::NOERROR;
+            response.authorities.push(soa_record(ttl));
+        }
+        QueryType::OPT => {
+            warn!("received OPT pseudo record as question: {:?}", &query);
+            response.header.rescode = ResultCode::FORMERR;
         }
         QueryType::UNKNOWN(x) => {
             warn!("received query of unsupported type ({}): {:?}", x, &query);
             response.header.rescode = ResultCode::SERVFAIL;
         }
     }
+    // We're the authority for the zone, but only for answers we could give
+    response.header.authoritative_answer = response.header.rescode == ResultCode::NOERROR;
     debug!("response is: {:#?}", &response);
     Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::lookup;
     use super::protocol::*;
+    use super::rate_limit::RateLimiter;
+    use super::{lookup, refuse, soa_record, DnsStats, Responder};
     use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
 
     macro_rules! lookup_tests {
         ($name:ident, $query_packet:expr, $response_code:expr, $extra_tests:expr) => {
             #[test]
             fn $name() {
-                let response = lookup($query_packet).unwrap();
+                let response = lookup($query_packet, 0).unwrap();
                 // a few common tests
                 assert_eq!($query_packet.header.id, response.header.id);
                 assert_eq!(response.header.rescode, $response_code);
@@ -132,7 +395,7 @@ mod tests {
       },
       ResultCode::NOERROR,
       |response: &DnsPacket| {
-        assert_eq!(response.header.recursion_desired, true);
+        assert!(response.header.recursion_desired);
         assert_eq!(
           response.questions[0].name, "hello.test",
           "response question's name doesn't match original name"
@@ -182,6 +445,74 @@ mod tests {
       }
     }
 
+    lookup_tests! {
+      zone_soa_requests_are_answered,
+      &packet_with_question("test".to_string(), QueryType::SOA),
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers, vec![soa_record(0)]);
+      }
+    }
+
+    lookup_tests! {
+      zone_ns_requests_are_answered_with_glue,
+      &packet_with_question("test".to_string(), QueryType::NS),
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert_eq!(
+          response.answers,
+          vec![DnsRecord::NS {
+            domain: "test".to_string(),
+            host: "ns.test".to_string(),
+            ttl: 0
+          }]
+        );
+        assert_eq!(
+          response.resources,
+          vec![DnsRecord::A {
+            domain: "ns.test".to_string(),
+            addr: Ipv4Addr::LOCALHOST,
+            ttl: 0
+          }]
+        );
+      }
+    }
+
+    lookup_tests! {
+      unknown_query_types_are_not_authoritative,
+      &packet_with_question("hello.test".to_string(), QueryType::UNKNOWN(99)),
+      ResultCode::SERVFAIL,
+      |response: &DnsPacket| {
+        assert!(!response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 0);
+      }
+    }
+
+    lookup_tests! {
+      empty_answers_include_soa_authority,
+      &packet_with_question("hello.test".to_string(), QueryType::AAAA),
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authorities, vec![soa_record(0)]);
+      }
+    }
+
+    #[test]
+    fn answers_use_configured_ttl() {
+        let request = packet_with_question("hello.test".to_string(), QueryType::A);
+        let response = lookup(&request, 60).unwrap();
+        assert_eq!(
+            response.answers[0],
+            DnsRecord::A {
+                domain: "hello.test".to_string(),
+                addr: Ipv4Addr::LOCALHOST,
+                ttl: 60
+            }
+        );
+    }
+
     lookup_tests! {
       packets_with_no_queries_are_not_implemented,
       {
@@ -224,6 +555,122 @@ mod tests {
         }
     }
 
+    lookup_tests! {
+      edns_requests_get_opt_record_in_response,
+      {
+        let mut packet = packet_with_question("hello.test".to_string(), QueryType::A);
+        packet.resources.push(DnsRecord::OPT { packet_len: 4096, flags: 0 });
+        &packet.clone()
+      },
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+          response.resources,
+          vec![DnsRecord::OPT { packet_len: 512, flags: 0 }]
+        );
+      }
+    }
+
+    lookup_tests! {
+      unsupported_edns_versions_get_badvers,
+      {
+        let mut packet = packet_with_question("hello.test".to_string(), QueryType::A);
+        packet.resources.push(DnsRecord::OPT { packet_len: 4096, flags: 1 << 16 });
+        &packet.clone()
+      },
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(
+          response.resources,
+          vec![DnsRecord::OPT { packet_len: 512, flags: 1 << 24 }]
+        );
+      }
+    }
+
+    lookup_tests! {
+      requests_without_edns_get_no_opt_record,
+      &packet_with_question("hello.test".to_string(), QueryType::A),
+      ResultCode::NOERROR,
+      |response: &DnsPacket| {
+        assert_eq!(response.resources.len(), 0);
+      }
+    }
+
+    #[test]
+    fn refused_responses_echo_id_and_question() {
+        let request = packet_with_question("hello.test".to_string(), QueryType::A);
+        let response = refuse(&request);
+        assert_eq!(response.header.id, request.header.id);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        assert_eq!(response.questions, request.questions);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn malformed_packets_count_towards_rate_limit() {
+        let mut responder = responder(2);
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 5353));
+        // a question whose name points at itself
+        let garbage = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12];
+        for _ in 0..3 {
+            let mut buffer = BytePacketBuffer::new();
+            buffer.buf[..garbage.len()].copy_from_slice(&garbage);
+            assert_eq!(responder.handle(&mut buffer, peer), None);
+        }
+
+        let mut request = packet_with_question("hello.test".to_string(), QueryType::A);
+        let response = respond(&mut responder, &mut request);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        assert_eq!(responder.stats.lock().unwrap().malformed, 3);
+    }
+
+    #[test]
+    fn oversized_responses_are_truncated() {
+        // 238 characters, the answer barely fits without EDNS
+        let name = format!(
+            "{}.{}.test",
+            vec!["a".repeat(63); 3].join("."),
+            "b".repeat(41)
+        );
+        let mut request = packet_with_question(name, QueryType::A);
+        let response = respond(&mut responder(10), &mut request);
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 1);
+
+        request.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+        });
+        let response = respond(&mut responder(10), &mut request);
+        assert!(response.header.truncated_message);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.questions, request.questions[..1]);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.edns_version(), Some(0));
+    }
+
+    fn responder(queries_per_second: u32) -> Responder {
+        Responder {
+            stats: Arc::new(Mutex::new(DnsStats::default())),
+            rate_limiter: RateLimiter::new(queries_per_second),
+            ttl: 0,
+            log_unexpected_sources: false,
+        }
+    }
+
+    fn respond(responder: &mut Responder, request: &mut DnsPacket) -> DnsPacket {
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 5353));
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        let data = responder.handle(&mut buffer, peer).unwrap();
+        let mut response = BytePacketBuffer::new();
+        response.buf[..data.len()].copy_from_slice(&data);
+        DnsPacket::from_buffer(&mut response).unwrap()
+    }
+
     fn packet_with_question(name: String, query_type: QueryType) -> DnsPacket {
         let mut packet = DnsPacket::new();
         packet.header.id = 10;
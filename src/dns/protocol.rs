@@ -1,20 +1,78 @@
 // TODO: may enable these when I'll understand the math better
 #![allow(clippy::cast_lossless, clippy::identity_op)]
+// Record and result code names follow the RFCs
+#![allow(clippy::upper_case_acronyms)]
+use std::error;
+use std::fmt;
 use std::io::Result;
 use std::io::{Error, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use log::warn;
 
+const BUFFER_SIZE: usize = 512;
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 255;
+// Compressed names rarely need more than one or two jumps. The limit protects
+// against pointer loops in malformed packets.
+const MAX_JUMPS: usize = 5;
+
+/// Errors caused by malformed packets (or names that can't be encoded).
+/// Surfaced as `io::Error`s of kind `InvalidInput` wrapping this type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    EndOfBuffer,
+    LabelTooLong,
+    NameTooLong,
+    EmptyLabel,
+    TooManyJumps,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            ProtocolError::EndOfBuffer => "End of buffer",
+            ProtocolError::LabelTooLong => "Single label exceeds 63 characters of length",
+            ProtocolError::NameTooLong => "Name exceeds 255 characters of length",
+            ProtocolError::EmptyLabel => "Name contains an empty label",
+            ProtocolError::TooManyJumps => "Limit of jumps exceeded while reading name",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+impl ProtocolError {
+    /// The protocol error wrapped by `err`, if any.
+    pub fn from_io(err: &Error) -> Option<ProtocolError> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ProtocolError>())
+            .copied()
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Error {
+        Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: [u8; BUFFER_SIZE],
     pub pos: usize,
 }
 
+impl Default for BytePacketBuffer {
+    fn default() -> BytePacketBuffer {
+        BytePacketBuffer::new()
+    }
+}
+
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: [0; BUFFER_SIZE],
             pos: 0,
         }
     }
@@ -24,6 +82,9 @@ impl BytePacketBuffer {
     }
 
     fn step(&mut self, steps: usize) -> Result<()> {
+        if self.pos + steps > BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
+        }
         self.pos += steps;
 
         Ok(())
@@ -36,8 +97,8 @@ impl BytePacketBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if self.pos >= BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -46,17 +107,17 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if pos >= BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
         }
         Ok(self.buf[pos])
     }
 
     pub(super) fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if start + len > BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     fn read_u16(&mut self) -> Result<u16> {
@@ -77,6 +138,9 @@ impl BytePacketBuffer {
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jumped = false;
+        let mut jumps = 0;
+        // Count the terminating zero byte up front
+        let mut name_len = 1;
 
         let mut delim = "";
         loop {
@@ -87,6 +151,10 @@ impl BytePacketBuffer {
             // handle this by jumping to the offset, setting a flag to indicate
             // that we shouldn't update the shared buffer position once done.
             if (len & 0xC0) == 0xC0 {
+                if jumps >= MAX_JUMPS {
+                    return Err(ProtocolError::TooManyJumps.into());
+                }
+
                 // When a jump is performed, we only modify the shared buffer
                 // position once, and avoid making the change later on.
                 if !jumped {
@@ -97,6 +165,7 @@ impl BytePacketBuffer {
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
                 pos = offset as usize;
                 jumped = true;
+                jumps += 1;
                 continue;
             }
 
@@ -107,6 +176,14 @@ impl BytePacketBuffer {
                 break;
             }
 
+            if len as usize > MAX_LABEL_LENGTH {
+                return Err(ProtocolError::LabelTooLong.into());
+            }
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LENGTH {
+                return Err(ProtocolError::NameTooLong.into());
+            }
+
             outstr.push_str(delim);
 
             let str_buffer = self.get_range(pos, len as usize)?;
@@ -125,8 +202,8 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if self.pos >= BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -156,15 +233,25 @@ impl BytePacketBuffer {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
+        // The root name is just the terminating empty label
+        if qname.is_empty() {
+            return self.write_u8(0);
+        }
+
+        // Each label is prefixed by its length, plus the terminating zero byte
+        if qname.len() + 2 > MAX_NAME_LENGTH {
+            return Err(ProtocolError::NameTooLong.into());
+        }
+
         let split_str = qname.split('.').collect::<Vec<&str>>();
 
         for label in split_str {
             let len = label.len();
-            if len > 0x34 {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Single label exceeds 63 characters of length",
-                ));
+            if len == 0 {
+                return Err(ProtocolError::EmptyLabel.into());
+            }
+            if len > MAX_LABEL_LENGTH {
+                return Err(ProtocolError::LabelTooLong.into());
             }
 
             self.write_u8(len as u8)?;
@@ -179,6 +266,9 @@ impl BytePacketBuffer {
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= BUFFER_SIZE {
+            return Err(ProtocolError::EndOfBuffer.into());
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -192,7 +282,7 @@ impl BytePacketBuffer {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -210,7 +300,7 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            _ => ResultCode::NOERROR,
         }
     }
 }
@@ -237,6 +327,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> DnsHeader {
+        DnsHeader::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -296,7 +392,7 @@ impl DnsHeader {
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
                 | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
@@ -325,6 +421,7 @@ pub enum QueryType {
     SOA,   // 6
     MX,    // 15
     AAAA,  // 28
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -337,6 +434,7 @@ impl QueryType {
             QueryType::SOA => 6,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
         }
     }
 
@@ -348,6 +446,7 @@ impl QueryType {
             6 => QueryType::SOA,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -428,6 +527,13 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    // EDNS0 pseudo record (RFC 6891). The class field carries the UDP
+    // payload size and the ttl field the extended rcode, version and flags.
+    // Options are ignored.
+    OPT {
+        packet_len: u16,
+        flags: u32,
+    }, // 41
 }
 
 impl DnsRecord {
@@ -437,7 +543,7 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
@@ -528,6 +634,14 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::OPT => {
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                })
+            }
             QueryType::UNKNOWN(_) => {
                 buffer.step(data_len as usize)?;
 
@@ -663,6 +777,13 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
+            DnsRecord::OPT { packet_len, flags } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(0)?;
+            }
             DnsRecord::UNKNOWN { .. } => {
                 warn!("Skipping record: {:?}", self);
             }
@@ -681,6 +802,12 @@ pub struct DnsPacket {
     pub resources: Vec<DnsRecord>,
 }
 
+impl Default for DnsPacket {
+    fn default() -> DnsPacket {
+        DnsPacket::new()
+    }
+}
+
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -718,6 +845,14 @@ impl DnsPacket {
         Ok(result)
     }
 
+    /// The EDNS version of the packet's OPT record, if it has one.
+    pub fn edns_version(&self) -> Option<u8> {
+        self.resources.iter().find_map(|rec| match *rec {
+            DnsRecord::OPT { flags, .. } => Some((flags >> 16) as u8),
+            _ => None,
+        })
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
@@ -742,3 +877,228 @@ impl DnsPacket {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(data: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[..data.len()].copy_from_slice(data);
+        buffer
+    }
+
+    fn protocol_error(err: Error) -> ProtocolError {
+        *err.into_inner()
+            .and_then(|e| e.downcast::<ProtocolError>().ok())
+            .expect("not a protocol error")
+    }
+
+    fn sample_packet() -> BytePacketBuffer {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 7;
+        packet
+            .questions
+            .push(DnsQuestion::new("www.hello.test".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.hello.test".to_string(),
+            host: "hello.test".to_string(),
+            ttl: 0,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn opt_records_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0x0000_8000,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert_eq!(buffer.pos(), 12 + 11);
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.resources, packet.resources);
+        assert_eq!(parsed.edns_version(), Some(0));
+    }
+
+    #[test]
+    fn jump_loops_are_rejected() {
+        // a name pointing at itself
+        let mut buffer = buffer_with(&[0xC0, 0x00]);
+        let err = buffer.read_qname(&mut String::new()).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::TooManyJumps);
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        let mut buffer = buffer_with(&[
+            5, b'h', b'e', b'l', b'l', b'o', 0, 3, b'w', b'w', b'w', 0xC0, 0x00,
+        ]);
+        buffer.seek(7).unwrap();
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(name, "www.hello");
+        assert_eq!(buffer.pos(), 13);
+    }
+
+    #[test]
+    fn oversized_labels_are_rejected() {
+        let mut buffer = buffer_with(&[64]);
+        let err = buffer.read_qname(&mut String::new()).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::LabelTooLong);
+    }
+
+    #[test]
+    fn oversized_names_are_rejected() {
+        let mut data = vec![];
+        for _ in 0..5 {
+            data.push(60);
+            data.extend_from_slice(&[b'a'; 60]);
+        }
+        data.push(0);
+        let mut buffer = buffer_with(&data);
+        let err = buffer.read_qname(&mut String::new()).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::NameTooLong);
+    }
+
+    fn name_with_octets(octets: usize) -> Vec<u8> {
+        // four labels of 62 characters take 253 octets with the root byte
+        let mut data = vec![];
+        for _ in 0..4 {
+            data.push(62);
+            data.extend_from_slice(&[b'a'; 62]);
+        }
+        let last = octets - 253 - 1;
+        data.push(last as u8);
+        data.extend_from_slice(&vec![b'b'; last]);
+        data.push(0);
+        assert_eq!(data.len(), octets);
+        data
+    }
+
+    #[test]
+    fn names_up_to_255_octets_are_accepted() {
+        let mut buffer = buffer_with(&name_with_octets(255));
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(buffer.pos(), 255);
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&name).unwrap();
+        assert_eq!(buffer.pos(), 255);
+    }
+
+    #[test]
+    fn names_of_256_octets_are_rejected() {
+        let mut buffer = buffer_with(&name_with_octets(256));
+        let err = buffer.read_qname(&mut String::new()).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::NameTooLong);
+
+        let name = format!("{}.bb", vec!["a".repeat(62); 4].join("."));
+        let err = BytePacketBuffer::new().write_qname(&name).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::NameTooLong);
+    }
+
+    #[test]
+    fn writing_labels_up_to_63_characters() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&"a".repeat(63)).unwrap();
+        let err = buffer.write_qname(&"a".repeat(64)).unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::LabelTooLong);
+    }
+
+    #[test]
+    fn root_name_is_a_single_zero_byte() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("").unwrap();
+        assert_eq!(buffer.pos(), 1);
+        assert_eq!(buffer.buf[0], 0);
+        let err = buffer.write_qname("a..test").unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::EmptyLabel);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 3;
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet
+            .questions
+            .push(DnsQuestion::new("".to_string(), QueryType::NS));
+        packet
+            .questions
+            .push(DnsQuestion::new("hello.test".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::A {
+            domain: "hello.test".to_string(),
+            addr: Ipv4Addr::LOCALHOST,
+            ttl: 5,
+        });
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(
+            format!("{:?}", parsed.header),
+            format!("{:?}", packet.header)
+        );
+        assert_eq!(parsed.questions, packet.questions);
+        assert_eq!(parsed.answers, packet.answers);
+        assert_eq!(parsed.authorities, packet.authorities);
+        assert_eq!(parsed.resources, packet.resources);
+    }
+
+    #[test]
+    fn range_may_end_at_buffer_end() {
+        let mut buffer = BytePacketBuffer::new();
+        assert_eq!(buffer.get_range(0, BUFFER_SIZE).unwrap().len(), BUFFER_SIZE);
+        assert!(buffer.get_range(1, BUFFER_SIZE).is_err());
+    }
+
+    #[test]
+    fn out_of_bounds_access_is_an_error() {
+        let mut buffer = BytePacketBuffer::new();
+        assert!(buffer.set(BUFFER_SIZE, 1).is_err());
+        assert!(buffer.set_u16(BUFFER_SIZE - 1, 1).is_err());
+        assert!(buffer.step(BUFFER_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn truncated_packets_do_not_panic() {
+        let sample = sample_packet();
+        assert!(DnsPacket::from_buffer(&mut buffer_with(&sample.buf[..sample.pos])).is_ok());
+        for len in 0..sample.pos {
+            // Zero the rest of the buffer and make sure the remainder (which
+            // doesn't match the header counts) never panics.
+            let mut buffer = buffer_with(&sample.buf[..len]);
+            let _ = DnsPacket::from_buffer(&mut buffer);
+        }
+    }
+
+    #[test]
+    fn garbage_packets_do_not_panic() {
+        // cheap deterministic pseudo random data
+        let mut seed: u32 = 0x1234_5678;
+        for _ in 0..1000 {
+            let mut buffer = BytePacketBuffer::new();
+            for b in buffer.buf.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *b = seed as u8;
+            }
+            if let Ok(mut packet) = DnsPacket::from_buffer(&mut buffer) {
+                let _ = packet.write(&mut BytePacketBuffer::new());
+            }
+        }
+    }
+}
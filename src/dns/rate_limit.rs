@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+// Forget about idle clients once we track this many.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits the number of queries each source address may send per second.
+pub(super) struct RateLimiter {
+    limit: u32,
+    clients: HashMap<IpAddr, Window>,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub(super) fn new(queries_per_second: u32) -> RateLimiter {
+        RateLimiter {
+            limit: queries_per_second,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Registers a query from `source` at `now` and returns the number of
+    /// queries over the limit in the current window (0 if it's allowed).
+    pub(super) fn check(&mut self, source: IpAddr, now: Instant) -> u32 {
+        if self.clients.len() >= PRUNE_THRESHOLD {
+            self.clients
+                .retain(|_, window| now.duration_since(window.start) < WINDOW);
+        }
+        let window = self.clients.entry(source).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count = window.count.saturating_add(1);
+        window.count.saturating_sub(self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn limits_each_source_per_window() {
+        let mut limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        let first = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert_eq!(limiter.check(first, now), 0);
+        assert_eq!(limiter.check(first, now), 0);
+        assert_eq!(limiter.check(first, now), 1);
+        assert_eq!(limiter.check(first, now), 2);
+        assert_eq!(limiter.check(second, now), 0);
+        assert_eq!(limiter.check(first, now + WINDOW), 0);
+    }
+}
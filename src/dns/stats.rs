@@ -0,0 +1,101 @@
+use super::protocol::{DnsPacket, QueryType, ResultCode};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Beyond this many distinct sources, queries from new sources are only
+// counted in `untracked_sources`.
+const MAX_TRACKED_SOURCES: usize = 1024;
+
+/// Counters for the queries handled by the dns server.
+#[derive(Clone, Debug, Default)]
+pub struct DnsStats {
+    pub by_qtype: HashMap<QueryType, u64>,
+    pub by_source: HashMap<IpAddr, u64>,
+    /// Queries from sources that didn't fit in `by_source`.
+    pub untracked_sources: u64,
+    pub by_outcome: HashMap<ResultCode, u64>,
+    /// Packets that couldn't be parsed (and were not answered).
+    pub malformed: u64,
+}
+
+impl DnsStats {
+    /// Records an answered query. Returns true if this is the first query
+    /// from `source`.
+    pub(super) fn record_query(
+        &mut self,
+        source: IpAddr,
+        request: &DnsPacket,
+        response: &DnsPacket,
+    ) -> bool {
+        if let Some(question) = request.questions.first() {
+            *self.by_qtype.entry(question.qtype).or_insert(0) += 1;
+        }
+        *self.by_outcome.entry(response.header.rescode).or_insert(0) += 1;
+        self.record_source(source)
+    }
+
+    /// Records a packet that failed to parse. Returns true if this is the
+    /// first query from `source`.
+    pub(super) fn record_malformed(&mut self, source: IpAddr) -> bool {
+        self.malformed += 1;
+        self.record_source(source)
+    }
+
+    // Sources we no longer track are never reported as new.
+    fn record_source(&mut self, source: IpAddr) -> bool {
+        if self.by_source.len() >= MAX_TRACKED_SOURCES && !self.by_source.contains_key(&source) {
+            self.untracked_sources += 1;
+            return false;
+        }
+        let count = self.by_source.entry(source).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::DnsQuestion;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn counts_queries_by_type_source_and_outcome() {
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new("hello.test".to_string(), QueryType::A));
+        let mut response = DnsPacket::new();
+        response.header.rescode = ResultCode::SERVFAIL;
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        let mut stats = DnsStats::default();
+        assert!(stats.record_query(localhost, &request, &response));
+        assert!(!stats.record_query(localhost, &request, &response));
+        assert!(stats.record_malformed(other));
+
+        assert_eq!(stats.by_qtype[&QueryType::A], 2);
+        assert_eq!(stats.by_outcome[&ResultCode::SERVFAIL], 2);
+        assert_eq!(stats.by_source[&localhost], 2);
+        assert_eq!(stats.by_source[&other], 1);
+        assert_eq!(stats.malformed, 1);
+    }
+
+    #[test]
+    fn stops_tracking_new_sources_when_full() {
+        let mut stats = DnsStats::default();
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            assert!(stats.record_malformed(IpAddr::V4(Ipv4Addr::from(i))));
+        }
+        let known = IpAddr::V4(Ipv4Addr::from(0));
+        let new = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(!stats.record_malformed(known));
+        assert!(!stats.record_malformed(new));
+
+        assert_eq!(stats.by_source.len(), MAX_TRACKED_SOURCES);
+        assert_eq!(stats.by_source[&known], 2);
+        assert_eq!(stats.untracked_sources, 1);
+    }
+}
@@ -0,0 +1,34 @@
+//! Launchd socket activation.
+//!
+//! Sockets declared under the `Sockets` key of the agent's plist are bound by
+//! launchd (so they may use privileged ports) and handed over to us on
+//! request.
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::slice;
+
+extern "C" {
+    fn launch_activate_socket(name: *const c_char, fds: *mut *mut c_int, cnt: *mut usize) -> c_int;
+    fn free(ptr: *mut c_void);
+}
+
+/// Returns the file descriptors launchd opened for the socket entry `name`.
+pub fn activate_socket(name: &str) -> Result<Vec<RawFd>> {
+    let c_name = CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut fds: *mut c_int = ptr::null_mut();
+    let mut cnt: usize = 0;
+    let res = unsafe { launch_activate_socket(c_name.as_ptr(), &mut fds, &mut cnt) };
+    if res != 0 {
+        return Err(Error::from_raw_os_error(res));
+    }
+    if fds.is_null() {
+        return Ok(vec![]);
+    }
+    let result = unsafe { slice::from_raw_parts(fds, cnt).to_vec() };
+    unsafe { free(fds as *mut c_void) };
+    Ok(result)
+}
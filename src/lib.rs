@@ -1 +1,6 @@
 pub mod dns;
+#[cfg(target_os = "macos")]
+pub mod launchd;
+pub mod ports;
+#[cfg(feature = "test-support")]
+pub mod test_support;
@@ -0,0 +1,113 @@
+//! Helpers for explaining why a listener couldn't bind its port.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Replaces "address in use" bind errors with one naming the process holding
+/// the port (if lsof can tell) and what to do about it. Other errors are
+/// returned unchanged.
+pub fn bind_error(err: Error, protocol: Protocol, port: u16) -> Error {
+    if err.kind() != ErrorKind::AddrInUse {
+        return err;
+    }
+    let (owner, advice) = match port_owner(protocol, port) {
+        // macOS' AirPlay Receiver
+        Some((name, pid)) if name == "ControlCenter" => (
+            format!(" by {} (pid {})", name, pid),
+            "disable AirPlay Receiver in the system settings or configure a different port",
+        ),
+        Some((name, pid)) => (
+            format!(" by {} (pid {})", name, pid),
+            "stop the other process or configure a different port",
+        ),
+        None => (
+            "".to_string(),
+            "stop the other process or configure a different port",
+        ),
+    };
+    Error::new(
+        ErrorKind::AddrInUse,
+        format!(
+            "{} port {} is already in use{}: {}",
+            protocol, port, owner, advice
+        ),
+    )
+}
+
+/// The name and pid of the process bound to the port, according to lsof.
+fn port_owner(protocol: Protocol, port: u16) -> Option<(String, u32)> {
+    let mut cmd = Command::new("lsof");
+    cmd.arg("-nP").arg("-Fpc");
+    match protocol {
+        Protocol::Tcp => cmd.arg(format!("-iTCP:{}", port)).arg("-sTCP:LISTEN"),
+        Protocol::Udp => cmd.arg(format!("-iUDP:{}", port)),
+    };
+    let output = cmd.output().ok()?;
+    parse_lsof_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_lsof_output(output: &str) -> Option<(String, u32)> {
+    let mut pid = None;
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix('p') {
+            pid = value.parse().ok();
+        } else if let Some(name) = line.strip_prefix('c') {
+            return pid.map(|pid| (name.to_string(), pid));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    #[test]
+    fn parses_process_from_lsof_output() {
+        let output = "p123\ncmDNSResponder\np456\ncother\n";
+        assert_eq!(
+            parse_lsof_output(output),
+            Some(("mDNSResponder".to_string(), 123))
+        );
+        assert_eq!(parse_lsof_output(""), None);
+    }
+
+    #[test]
+    fn explains_ports_in_use() {
+        let taken = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let err = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).unwrap_err();
+        let err = bind_error(err, Protocol::Udp, port);
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("udp port {} is already in use", port)));
+    }
+
+    #[test]
+    fn other_errors_are_unchanged() {
+        let err = bind_error(
+            Error::new(ErrorKind::PermissionDenied, "boom"),
+            Protocol::Tcp,
+            80,
+        );
+        assert_eq!(err.to_string(), "boom");
+    }
+}
@@ -0,0 +1,65 @@
+//! Helpers for running duwop servers inside tests.
+//!
+//! Enabled with the `test-support` feature. Servers are bound to ephemeral
+//! ports on localhost and run on a dedicated runtime which is shut down when
+//! the [`TestServers`] instance is dropped.
+
+use crate::dns::protocol::{BytePacketBuffer, DnsPacket, DnsQuestion, QueryType};
+use crate::dns::{DNSServer, DnsStats};
+
+use std::io::Result;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use tokio::runtime::Runtime;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct TestServers {
+    pub dns_addr: SocketAddr,
+    pub dns_stats: Arc<Mutex<DnsStats>>,
+    _runtime: Runtime,
+}
+
+impl TestServers {
+    /// Starts all servers on ephemeral ports.
+    pub fn start() -> Result<TestServers> {
+        let runtime = Runtime::new()?;
+        let dns_server = DNSServer::new(0)?;
+        let dns_addr = dns_server.local_addr()?;
+        let dns_stats = dns_server.stats();
+        runtime.spawn(async move {
+            if let Err(e) = dns_server.run().await {
+                error!("dns server failed: {}", e);
+            }
+        });
+        Ok(TestServers {
+            dns_addr,
+            dns_stats,
+            _runtime: runtime,
+        })
+    }
+
+    /// Sends a single question to the dns server and returns the parsed
+    /// response.
+    pub fn dns_query(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let mut request = DnsPacket::new();
+        request.header.id = 1;
+        request.header.recursion_desired = true;
+        request
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype));
+        let mut req_buffer = BytePacketBuffer::new();
+        request.write(&mut req_buffer)?;
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+        socket.send_to(&req_buffer.buf[..req_buffer.pos], self.dns_addr)?;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        socket.recv_from(&mut res_buffer.buf)?;
+        DnsPacket::from_buffer(&mut res_buffer)
+    }
+}
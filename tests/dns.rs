@@ -0,0 +1,78 @@
+use duwop::dns::protocol::*;
+use duwop::test_support::TestServers;
+
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+#[test]
+fn resolves_test_domains_to_localhost() {
+    let servers = TestServers::start().unwrap();
+    let response = servers.dns_query("myapp.test", QueryType::A).unwrap();
+    assert_eq!(response.header.id, 1);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(
+        response.answers,
+        vec![DnsRecord::A {
+            domain: "myapp.test".to_string(),
+            addr: Ipv4Addr::LOCALHOST,
+            ttl: 0
+        }]
+    );
+}
+
+#[test]
+fn refuses_to_serve_other_domains() {
+    let servers = TestServers::start().unwrap();
+    let response = servers.dns_query("example.com", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn keeps_serving_after_malformed_packets() {
+    let servers = TestServers::start().unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    // a question whose name points at itself
+    let packet = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12];
+    socket.send_to(&packet, servers.dns_addr).unwrap();
+
+    let response = servers.dns_query("myapp.test", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    let stats = servers.dns_stats.lock().unwrap();
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.by_qtype[&QueryType::A], 1);
+    assert_eq!(stats.by_outcome[&ResultCode::NOERROR], 1);
+}
+
+#[test]
+fn answers_bursts_of_queries() {
+    let servers = TestServers::start().unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let ids: HashSet<u16> = (1..=200).collect();
+    for id in &ids {
+        let mut packet = DnsPacket::new();
+        packet.header.id = *id;
+        packet
+            .questions
+            .push(DnsQuestion::new(format!("app{}.test", id), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        socket
+            .send_to(&buffer.buf[..buffer.pos], servers.dns_addr)
+            .unwrap();
+    }
+
+    let mut answered = HashSet::new();
+    for _ in &ids {
+        let mut buffer = BytePacketBuffer::new();
+        socket.recv_from(&mut buffer.buf).unwrap();
+        let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        answered.insert(response.header.id);
+    }
+    assert_eq!(answered, ids);
+}